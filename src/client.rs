@@ -26,9 +26,12 @@ use crossbeam::queue::SegQueue;
 use sha2::{Digest, Sha256};
 use std::convert::TryFrom;
 use std::io::prelude::*;
+use std::net::TcpListener;
 use std::os::unix::net::UnixStream;
 use std::process::exit;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
+use std::thread;
 use std::time::Instant;
 
 use isla_lib::concrete::{B64, BV};
@@ -46,10 +49,124 @@ use opts::CommonOpts;
 
 enum Answer<'a> {
     Error,
-    Version (&'a[u8]),
+    Version (u32, &'a[u8]),
     StartTraces,
     Trace(bool, &'a[u8]),
-    EndTraces
+    EndTraces(bool),
+    Negotiated(&'a[u8]),
+    Disassembly(&'a[u8]),
+}
+
+/// Bumped whenever the `Answer` framing grows a tag that an older ReadDwarf
+/// build wouldn't know how to parse. Sent alongside the git commit hash in
+/// the `version` answer so a client can tell the two apart.
+const PROTOCOL_VERSION: u32 = 2;
+
+/// Feature tokens a client may request via `negotiate`. Emission of any
+/// answer tag added after `PROTOCOL_VERSION` 1 is gated on the matching
+/// token having been granted, so new capabilities can be added without
+/// breaking clients that never negotiate.
+///
+/// `tcp` vs. Unix socket is a choice made once at server start (see `--tcp`/
+/// `--socket`), not a per-connection answer-tag feature, so it has no entry
+/// or match arm here.
+const SERVER_CAPABILITIES: &[&str] = &["truncated-traces", "disasm"];
+
+#[derive(Default)]
+struct Capabilities {
+    truncated_traces: bool,
+    disasm: bool,
+}
+
+impl Capabilities {
+    /// Intersect a client's requested, comma-separated capability tokens
+    /// with `SERVER_CAPABILITIES`, returning the capabilities to enable
+    /// plus the tokens granted (for echoing back to the client).
+    fn negotiate(requested: &str) -> (Capabilities, Vec<&str>) {
+        let mut capabilities = Capabilities::default();
+        let mut granted = Vec::new();
+        for token in requested.split(',').map(str::trim).filter(|t| !t.is_empty()) {
+            if SERVER_CAPABILITIES.contains(&token) {
+                granted.push(token);
+                match token {
+                    "truncated-traces" => capabilities.truncated_traces = true,
+                    "disasm" => capabilities.disasm = true,
+                    _ => (),
+                }
+            }
+        }
+        (capabilities, granted)
+    }
+}
+
+/// A token-bucket limiter used to cap how many `Answer::Trace` messages a
+/// single `execute_opcode` call may emit per second, so a single
+/// pathological instruction cannot flood the socket or starve other
+/// requests. The refill timestamp (nanoseconds since `epoch`) and the
+/// remaining token count are packed into a single `AtomicU64` so a trace
+/// can be admitted with one lock-free compare-and-swap.
+struct RateLimiter {
+    epoch: Instant,
+    rate_per_sec: u64,
+    burst: u64,
+    packed: AtomicU64,
+}
+
+impl RateLimiter {
+    const TOKEN_BITS: u32 = 24;
+    const TOKEN_MASK: u64 = (1 << Self::TOKEN_BITS) - 1;
+
+    fn new(rate_per_sec: u64, burst: u64) -> Self {
+        let burst = burst.min(Self::TOKEN_MASK);
+        RateLimiter { epoch: Instant::now(), rate_per_sec, burst, packed: AtomicU64::new(burst) }
+    }
+
+    fn pack(nanos: u64, tokens: u64) -> u64 {
+        (nanos << Self::TOKEN_BITS) | (tokens & Self::TOKEN_MASK)
+    }
+
+    fn unpack(word: u64) -> (u64, u64) {
+        (word >> Self::TOKEN_BITS, word & Self::TOKEN_MASK)
+    }
+
+    /// Try to take a single token, refilling the bucket first based on how
+    /// much time has elapsed since the last refill. Returns `false` once
+    /// the bucket is empty and the configured rate hasn't produced a fresh
+    /// token yet.
+    fn try_acquire(&self) -> bool {
+        loop {
+            let now_nanos = self.epoch.elapsed().as_nanos() as u64;
+            let current = self.packed.load(Ordering::Relaxed);
+            let (last_nanos, tokens) = Self::unpack(current);
+            let elapsed = now_nanos.saturating_sub(last_nanos) as u128;
+
+            // Widen to u128 so `elapsed * rate_per_sec` can't overflow, however
+            // large the configured rate is.
+            let refilled = (elapsed * self.rate_per_sec as u128) / 1_000_000_000;
+
+            // Only advance the refill clock by the slice of `elapsed` that was
+            // actually converted into whole tokens, carrying the leftover
+            // fractional nanoseconds over to the next call. Snapping to
+            // `now_nanos` unconditionally would discard that remainder every
+            // time and could stall refills forever at rates slower than one
+            // token per call.
+            let consumed_nanos = if refilled == 0 {
+                0
+            } else {
+                ((refilled * 1_000_000_000) / self.rate_per_sec as u128) as u64
+            };
+
+            let refilled = refilled.min(self.burst as u128) as u64;
+            let tokens = self.burst.min(tokens.saturating_add(refilled));
+            if tokens == 0 {
+                return false;
+            }
+            let new = Self::pack(last_nanos + consumed_nanos, tokens - 1);
+            if self.packed.compare_exchange_weak(current, new, Ordering::Relaxed, Ordering::Relaxed).is_ok() {
+                return true;
+            }
+        }
+    }
 }
 
 
@@ -79,8 +196,9 @@ fn write_answer<W: Write>(writer: &mut W, message: Answer) -> std::io::Result<()
             writer.write_all(&[0])?;
             Ok(())
         }
-        Answer::Version(ver) => {
+        Answer::Version(proto, ver) => {
             writer.write_all(&[1])?;
+            writer.write_all(&proto.to_le_bytes())?;
             write_slice(writer, ver)?;
             Ok(())
         }
@@ -93,21 +211,78 @@ fn write_answer<W: Write>(writer: &mut W, message: Answer) -> std::io::Result<()
             write_slice(writer, trc)?;
             Ok(())
         }
-        Answer::EndTraces => {
-            writer.write_all(&[4])?;
+        Answer::EndTraces(truncated) => {
+            writer.write_all(&[4, u8::from(truncated)])?;
+            Ok(())
+        }
+        Answer::Negotiated(caps) => {
+            writer.write_all(&[7])?;
+            write_slice(writer, caps)?;
+            Ok(())
+        }
+        Answer::Disassembly(asm) => {
+            writer.write_all(&[8])?;
+            write_slice(writer, asm)?;
             Ok(())
         }
     }
 }
 
 
-fn execute_opcode(
-    stream: &mut UnixStream,
+/// Monotonic counter used to keep concurrent `disassemble_opcode` calls
+/// (each TCP connection is handled on its own thread, see `isla_main`) from
+/// colliding on the same temporary file.
+static DISASM_TMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Whether `objdump_flags` names the target architecture (`-m...` or
+/// `--architecture...`). objdump has to be told which architecture a raw `-b binary` blob holds,
+/// or it falls back to guessing (and frequently gets it wrong or emits
+/// nothing). Rather than silently trusting the operator to have remembered
+/// `-m`/`--architecture` in `objdump_flags`, require it explicitly.
+fn has_architecture_flag(objdump_flags: &[String]) -> bool {
+    objdump_flags.iter().any(|flag| {
+        flag == "-m" || flag.starts_with("-m") || flag.starts_with("--architecture")
+    })
+}
+
+/// Shell out to the `objdump` resolved from the ISA config to turn a raw
+/// opcode back into assembly text, the inverse of `assemble_instruction`.
+fn disassemble_opcode(opcode_bytes: &[u8], isa_config: &ISAConfig<B64>) -> Result<String, String> {
+    if !has_architecture_flag(&isa_config.objdump_flags) {
+        return Err(format!(
+            "Configuration option `objdump_flags` must specify the target architecture, e.g. objdump_flags = [\"-m\", \"aarch64\"]"
+        ));
+    }
+
+    let unique = DISASM_TMP_COUNTER.fetch_add(1, Ordering::Relaxed);
+    let mut path = std::env::temp_dir();
+    path.push(format!("isla-client-disasm-{}-{}.bin", std::process::id(), unique));
+    std::fs::write(&path, opcode_bytes).map_err(|e| format!("Could not write temporary opcode file: {}", e))?;
+
+    let result = std::process::Command::new(&isa_config.objdump)
+        .args(&isa_config.objdump_flags)
+        .args(&["-D", "-b", "binary"])
+        .arg(&path)
+        .output();
+
+    let _ = std::fs::remove_file(&path);
+
+    match result {
+        Ok(output) if output.status.success() => Ok(String::from_utf8_lossy(&output.stdout).to_string()),
+        Ok(output) => Err(format!("objdump failed: {}", String::from_utf8_lossy(&output.stderr))),
+        Err(e) => Err(format!("Could not run objdump: {}", e)),
+    }
+}
+
+fn execute_opcode<S: Read + Write>(
+    stream: &mut S,
     opcode: B64,
     num_threads: usize,
     shared_state: &SharedState<B64>,
     register_state: &Bindings<B64>,
     letbindings: &Bindings<B64>,
+    isa_config: &ISAConfig<B64>,
+    capabilities: &Capabilities,
 ) -> std::io::Result<Result<(), String>> {
     let function_id = shared_state.symtab.lookup("zisla_client");
     let (args, _, instrs) = shared_state.functions.get(&function_id).unwrap();
@@ -117,6 +292,7 @@ fn execute_opcode(
         .task(0);
 
     let queue = Arc::new(SegQueue::new());
+    let rate_limiter = RateLimiter::new(isa_config.max_traces_per_second, isa_config.burst);
 
     // This is for signalling that the answer will have multiple messages in the bool+trace format
     write_answer(stream, Answer::StartTraces)?;
@@ -129,6 +305,14 @@ fn execute_opcode(
     Ok(loop {
         match queue.pop() {
             Ok(Ok((_, result, mut events))) => {
+                // Only charge a token when a trace is actually about to be sent, not on
+                // every queue poll, otherwise empty/terminating polls starve the bucket.
+                if !rate_limiter.try_acquire() {
+                    // Only a client that negotiated "truncated-traces" is told the run was
+                    // cut short; older clients just see the (premature) end of the stream.
+                    write_answer(stream, Answer::EndTraces(capabilities.truncated_traces))?;
+                    break Ok(());
+                }
                 let mut buf = Vec::new();
                 let events: Vec<Event<B64>> = events.drain(..).rev().collect();
                 write_events(&mut buf, &events, &shared_state.symtab);
@@ -136,22 +320,24 @@ fn execute_opcode(
             }
             Ok(Err(msg)) => break Err(msg),
             Err(_) => {
-                write_answer(stream, Answer::EndTraces)?;
+                write_answer(stream, Answer::EndTraces(false))?;
                 break Ok(());
             }
         }
     })
 }
 
-fn interact(
-    stream: &mut UnixStream,
+fn interact<S: Read + Write>(
+    stream: &mut S,
     num_threads: usize,
     shared_state: &SharedState<B64>,
     register_state: &Bindings<B64>,
     letbindings: &Bindings<B64>,
     isa_config: &ISAConfig<B64>,
 ) -> std::io::Result<Result<(), String>> {
-    Ok(loop {
+    let mut capabilities = Capabilities::default();
+
+    Ok('outer: loop {
         // The parsing done here should match IslaServer.string_of_request of ReadDwarf
         let message = read_message(stream)?;
         let tmessage = message.trim();
@@ -160,12 +346,20 @@ fn interact(
                 // Protocol : Send a version answer
                 let mut s : String = "dev-".to_string();
                 s.push_str(env!("GIT_COMMIT"));
-                write_answer(stream, Answer::Version(s.as_bytes()))?;
+                write_answer(stream, Answer::Version(PROTOCOL_VERSION, s.as_bytes()))?;
+            }
+
+            ["negotiate", requested] => {
+                // Protocol : Send a Negotiated answer listing the granted capabilities
+                let (granted_capabilities, granted) = Capabilities::negotiate(requested);
+                capabilities = granted_capabilities;
+                let response = granted.join(",");
+                write_answer(stream, Answer::Negotiated(response.as_bytes()))?;
             }
 
             ["stop"] => {
                 // Protocol : Send nothing and shutdown
-                break Ok(())
+                break 'outer Ok(())
             }
 
             ["execute", instruction] => {
@@ -173,12 +367,12 @@ fn interact(
                 if let Ok(opcode) = u32::from_str_radix(&instruction, 16) {
                     let opcode = B64::from_u32(opcode);
                     match execute_opcode(stream, opcode, num_threads, shared_state,
-                                         register_state, letbindings)? {
+                                         register_state, letbindings, isa_config, &capabilities)? {
                         Ok(()) => continue,
-                        Err(msg) => break Err(msg),
+                        Err(msg) => break 'outer Err(msg),
                     }
                 } else {
-                    break Err(format!("Could not parse opcode {}", &instruction));
+                    break 'outer Err(format!("Could not parse opcode {}", &instruction));
                 }
             }
 
@@ -189,16 +383,35 @@ fn interact(
                     opcode.copy_from_slice(&bytes);
                     let opcode = B64::from_u32(u32::from_le_bytes(opcode));
                     match execute_opcode(stream, opcode, num_threads, shared_state,
-                                         register_state, letbindings)? {
+                                         register_state, letbindings, isa_config, &capabilities)? {
                         Ok(()) => continue,
-                        Err(msg) => break Err(msg),
+                        Err(msg) => break 'outer Err(msg),
+                    }
+                } else {
+                    break 'outer Err(format!("Could not parse opcode {}", &instruction));
+                }
+            }
+
+            ["disassemble", _] if !capabilities.disasm => {
+                write_answer(stream, Answer::Error)?;
+            }
+
+            ["disassemble", instruction] => {
+                // Protocol : Send a Disassembly answer
+                if let Ok(opcode) = u32::from_str_radix(&instruction, 16) {
+                    match disassemble_opcode(&opcode.to_le_bytes(), isa_config) {
+                        Ok(asm) => write_answer(stream, Answer::Disassembly(asm.as_bytes()))?,
+                        Err(msg) => {
+                            eprintln!("{}", msg);
+                            write_answer(stream, Answer::Error)?;
+                        }
                     }
                 } else {
-                    break Err(format!("Could not parse opcode {}", &instruction));
+                    break 'outer Err(format!("Could not parse opcode {}", &instruction));
                 }
             }
 
-            _ => break Err("Invalid command".to_string()),
+            _ => break 'outer Err("Invalid command".to_string()),
         }
     })
 }
@@ -209,9 +422,32 @@ fn main() {
     exit(code)
 }
 
+fn handle_connection<S: Read + Write>(
+    mut stream: S,
+    num_threads: usize,
+    shared_state: &SharedState<B64>,
+    register_state: &Bindings<B64>,
+    letbindings: &Bindings<B64>,
+    isa_config: &ISAConfig<B64>,
+) -> i32 {
+    match interact(&mut stream, num_threads, shared_state, register_state, letbindings, isa_config) {
+        Ok(Ok(())) => 0,
+        Ok(Err(isla_error)) => {
+            eprintln!("{}", isla_error);
+            write_answer(&mut stream, Answer::Error).expect("error on signalling error");
+            1
+        }
+        Err(io_error) => {
+            eprintln!("{}", io_error);
+            2
+        }
+    }
+}
+
 fn isla_main() -> i32 {
     let mut opts = opts::common_opts();
-    opts.reqopt("", "socket", "connect to server at location", "<path>");
+    opts.optopt("", "socket", "connect to server at location", "<path>");
+    opts.optopt("", "tcp", "listen for remote clients at address", "<addr:port>");
 
     let mut hasher = Sha256::new();
     let (matches, arch) = opts::parse(&mut hasher, &opts);
@@ -221,25 +457,60 @@ fn isla_main() -> i32 {
     let Initialized { regs, lets, shared_state } =
         initialize_architecture(&mut arch, symtab, &isa_config, AssertionMode::Optimistic);
 
-    let socket_path = matches.opt_str("socket").unwrap();
-    let mut stream = match UnixStream::connect(&socket_path) {
-        Ok(stream) => stream,
-        Err(e) => {
-            eprintln!("Could not connect to socket {}: {:?}", socket_path, e);
-            return 1;
+    match (matches.opt_str("socket"), matches.opt_str("tcp")) {
+        (Some(_), Some(_)) => {
+            eprintln!("Only one of --socket or --tcp may be specified");
+            1
         }
-    };
 
-    match interact(&mut stream, num_threads, &shared_state, &regs, &lets, &isa_config) {
-        Ok(Ok(())) => 0,
-        Ok(Err(isla_error)) => {
-            eprintln!("{}", isla_error);
-            write_answer(&mut stream, Answer::Error).expect("error on signalling error");
-            1
+        (Some(socket_path), None) => {
+            let stream = match UnixStream::connect(&socket_path) {
+                Ok(stream) => stream,
+                Err(e) => {
+                    eprintln!("Could not connect to socket {}: {:?}", socket_path, e);
+                    return 1;
+                }
+            };
+            handle_connection(stream, num_threads, &shared_state, &regs, &lets, &isa_config)
         }
-        Err(io_error) => {
-            eprintln!("{}", io_error);
-            2
+
+        (None, Some(addr)) => {
+            let listener = match TcpListener::bind(&addr) {
+                Ok(listener) => listener,
+                Err(e) => {
+                    eprintln!("Could not bind TCP listener on {}: {:?}", addr, e);
+                    return 1;
+                }
+            };
+
+            // The symbolic execution backend is stateless between connections, so a
+            // single SharedState (and the initial register/let bindings) can be shared
+            // by every concurrently connected client.
+            let shared_state = Arc::new(shared_state);
+            let regs = Arc::new(regs);
+            let lets = Arc::new(lets);
+            let isa_config = Arc::new(isa_config);
+
+            for conn in listener.incoming() {
+                match conn {
+                    Ok(stream) => {
+                        let shared_state = shared_state.clone();
+                        let regs = regs.clone();
+                        let lets = lets.clone();
+                        let isa_config = isa_config.clone();
+                        thread::spawn(move || {
+                            handle_connection(stream, num_threads, &shared_state, &regs, &lets, &isa_config);
+                        });
+                    }
+                    Err(e) => eprintln!("Failed to accept TCP connection: {:?}", e),
+                }
+            }
+            0
+        }
+
+        (None, None) => {
+            eprintln!("Either --socket or --tcp must be specified");
+            1
         }
     }
 }
@@ -57,6 +57,26 @@ fn get_tool_path(config: &Value, tool: &str) -> Result<PathBuf, String> {
     }
 }
 
+/// Tokens are added to the rate limiter's bucket this many times per
+/// second, and the bucket never holds more than this many tokens, unless
+/// overridden in the configuration file. These are deliberately finite:
+/// a default of `u64::MAX` would defeat the point of having a limiter at
+/// all.
+const DEFAULT_MAX_TRACES_PER_SECOND: u64 = 1000;
+const DEFAULT_BURST: u64 = 1000;
+
+fn get_rate_limit(config: &Value) -> (u64, u64) {
+    let max_traces_per_second = match config.get("max_traces_per_second") {
+        Some(Value::Integer(n)) if *n >= 0 => *n as u64,
+        _ => DEFAULT_MAX_TRACES_PER_SECOND,
+    };
+    let burst = match config.get("burst") {
+        Some(Value::Integer(n)) if *n >= 0 => *n as u64,
+        _ => DEFAULT_BURST,
+    };
+    (max_traces_per_second, burst)
+}
+
 /// Get the program counter from the ISA config, and map it to the
 /// correct register identifer in the shared state.
 fn get_program_counter(config: &Value, shared_state: &SharedState) -> Result<u32, String> {
@@ -72,11 +92,25 @@ fn get_program_counter(config: &Value, shared_state: &SharedState) -> Result<u32
     }
 }
  
+/// Extra flags (e.g. `-m aarch64`) passed to `objdump` so it disassembles
+/// raw opcodes for the configured architecture rather than guessing.
+fn get_objdump_flags(config: &Value) -> Vec<String> {
+    match config.get("objdump_flags") {
+        Some(Value::Array(flags)) => {
+            flags.iter().filter_map(|flag| flag.as_str().map(str::to_string)).collect()
+        }
+        _ => Vec::new(),
+    }
+}
+
 #[derive(Debug)]
 pub struct ISAConfig {
     pub pc: u32,
     pub assembler: PathBuf,
     pub objdump: PathBuf,
+    pub objdump_flags: Vec<String>,
+    pub max_traces_per_second: u64,
+    pub burst: u64,
 }
 
 pub fn load_config<P>(path: P, shared_state: &SharedState) -> Result<ISAConfig, String>
@@ -99,9 +133,14 @@ where
             return Err(format!("Error when parsing config '{}': {}", path.as_ref().display(), e)),
     };
 
+    let (max_traces_per_second, burst) = get_rate_limit(&config);
+
     Ok(ISAConfig {
 	pc: get_program_counter(&config, shared_state)?,
 	assembler: get_tool_path(&config, "assembler")?,
 	objdump: get_tool_path(&config, "objdump")?,
+	objdump_flags: get_objdump_flags(&config),
+	max_traces_per_second,
+	burst,
     })
 }